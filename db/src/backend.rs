@@ -0,0 +1,24 @@
+//! Per-backend glue needed to build a [`Pool`](crate::Pool)'s connection
+//! manager. Implementing this for a diesel-async connection type makes it
+//! usable as `Pool<T, Conn>`, mirroring how multi-backend Diesel setups
+//! enumerate their supported connection variants.
+
+use diesel_async::{AsyncConnection, pooled_connection::AsyncDieselConnectionManager};
+
+/// A diesel-async connection type that [`Pool`](crate::Pool) knows how to
+/// build a connection manager for.
+pub trait Backend: AsyncConnection + Send + 'static {
+    /// Backend-specific connection options, e.g. TLS configuration.
+    /// Backends with nothing to configure can set this to
+    /// `std::convert::Infallible`.
+    type Options: Send;
+
+    /// Build the connection manager used to check out connections of this
+    /// backend, applying `options` if given.
+    fn manager(
+        database_url: String,
+        options: Option<Self::Options>,
+    ) -> AsyncDieselConnectionManager<Self>
+    where
+        Self: Sized;
+}
@@ -0,0 +1,47 @@
+//! Registering handlers for job `kind`s.
+
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use futures_util::future::BoxFuture;
+
+/// The error type a handler may return. Job failures don't need to carry any
+/// structure beyond a message, since all we do with them is log
+/// `last_error` and decide whether to retry.
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A registered job handler: takes the job's payload and resolves once the
+/// work is done (or failed).
+pub type Handler =
+    Arc<dyn Fn(serde_json::Value) -> BoxFuture<'static, Result<(), HandlerError>> + Send + Sync>;
+
+/// Maps job `kind`s to the handler that should process them. Passed to a
+/// [`Worker`](crate::jobs::Worker), which looks up the handler for each
+/// claimed job by its `kind`.
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Handler>,
+}
+
+impl HandlerRegistry {
+    /// An empty registry; register handlers with [`HandlerRegistry::register`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` to process jobs enqueued with this `kind`.
+    #[must_use]
+    pub fn register<F, Fut>(mut self, kind: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        self.handlers
+            .insert(kind.into(), Arc::new(move |payload| Box::pin(handler(payload))));
+        self
+    }
+
+    /// Look up the handler registered for `kind`, if any.
+    pub(crate) fn get(&self, kind: &str) -> Option<&Handler> {
+        self.handlers.get(kind)
+    }
+}
@@ -0,0 +1,39 @@
+//! A durable, Postgres-backed background job queue built on top of a
+//! [`PgPool<ReadWrite>`](crate::PgPool), so axum handlers can enqueue work
+//! that survives process restarts instead of relying on fire-and-forget
+//! `tokio::spawn`.
+//!
+//! Consumers own the backing table, created via their own migrations:
+//!
+//! ```sql
+//! CREATE TABLE jobs (
+//!     id           bigserial PRIMARY KEY,
+//!     kind         text NOT NULL,
+//!     payload      jsonb NOT NULL,
+//!     run_at       timestamptz NOT NULL DEFAULT now(),
+//!     attempts     integer NOT NULL DEFAULT 0,
+//!     max_attempts integer NOT NULL DEFAULT 5,
+//!     state        text NOT NULL DEFAULT 'pending',
+//!     locked_by    text,
+//!     locked_at    timestamptz,
+//!     last_error   text
+//! );
+//!
+//! CREATE INDEX jobs_claim_idx ON jobs (run_at) WHERE state = 'pending';
+//! ```
+//!
+//! Handlers are registered by `kind` in a [`HandlerRegistry`], which a
+//! [`Worker`] polls, claiming pending rows with `SELECT ... FOR UPDATE SKIP
+//! LOCKED` so multiple workers can run concurrently without double-processing
+//! a job. Failures are rescheduled with exponential backoff until
+//! `max_attempts` is exhausted, at which point the row moves to the `dead`
+//! state. Rows left `running` past a lease timeout (e.g. because their
+//! worker crashed) are reclaimed back to `pending`.
+
+mod handler;
+mod queue;
+mod worker;
+
+pub use handler::{Handler, HandlerRegistry};
+pub use queue::{JobError, enqueue, enqueue_at};
+pub use worker::Worker;
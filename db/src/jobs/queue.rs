@@ -0,0 +1,207 @@
+//! Enqueueing and claiming rows of the `jobs` table. See the [module
+//! docs](crate::jobs) for the expected schema.
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    QueryableByName,
+    sql_types::{BigInt, Double, Integer, Jsonb, Text, Timestamptz},
+};
+use diesel_async::{
+    AsyncPgConnection, RunQueryDsl,
+    pooled_connection::deadpool::{Object, PoolError},
+    scoped_futures::ScopedFutureExt,
+};
+
+use crate::{PooledConnection, ReadWrite, WriteableConnection};
+
+/// A freshly-checked-out, read/write Postgres connection. Job claiming needs
+/// to run its own transaction, so (unlike the rest of this module) it can't
+/// be written generically against `impl WriteableConnection`.
+type PgWriteConn = PooledConnection<ReadWrite, Object<AsyncPgConnection>>;
+
+/// Errors that can occur while enqueueing or claiming jobs.
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    /// A query against the `jobs` table failed.
+    #[error("job queue query failed: {0}")]
+    Db(#[from] diesel::result::Error),
+
+    /// Failed to check out a connection from the pool.
+    #[error("failed to check out a connection: {0}")]
+    Pool(#[from] PoolError),
+}
+
+/// A row claimed off the queue, ready to be handed to its registered
+/// handler.
+#[derive(Debug, QueryableByName)]
+pub(crate) struct ClaimedJob {
+    #[diesel(sql_type = BigInt)]
+    pub id: i64,
+
+    #[diesel(sql_type = Text)]
+    pub kind: String,
+
+    #[diesel(sql_type = Jsonb)]
+    pub payload: serde_json::Value,
+
+    #[diesel(sql_type = Integer)]
+    pub attempts: i32,
+
+    #[diesel(sql_type = Integer)]
+    pub max_attempts: i32,
+}
+
+/// Enqueue a job of the given `kind`, to run as soon as a worker picks it
+/// up. Returns the new row's id.
+///
+/// Takes any [`WriteableConnection`], so it can be called standalone or
+/// alongside other writes inside an existing `transaction(|conn| ...)`
+/// callback, e.g. from a handler like `record` that wants to enqueue
+/// follow-up work atomically with the write that triggered it.
+pub async fn enqueue(
+    conn: &mut impl WriteableConnection,
+    kind: &str,
+    payload: serde_json::Value,
+) -> Result<i64, JobError> {
+    #[derive(QueryableByName)]
+    struct Id {
+        #[diesel(sql_type = BigInt)]
+        id: i64,
+    }
+
+    // `run_at` is the database's own `now()`, the clock `claim` compares
+    // against, not this process's clock.
+    let row: Id = diesel::sql_query(
+        "insert into jobs (kind, payload, run_at) values ($1, $2, now()) returning id",
+    )
+    .bind::<Text, _>(kind)
+    .bind::<Jsonb, _>(payload)
+    .get_result(conn)
+    .await?;
+
+    Ok(row.id)
+}
+
+/// Like [`enqueue`], but runs the job no earlier than `run_at`.
+pub async fn enqueue_at(
+    conn: &mut impl WriteableConnection,
+    kind: &str,
+    payload: serde_json::Value,
+    run_at: DateTime<Utc>,
+) -> Result<i64, JobError> {
+    #[derive(QueryableByName)]
+    struct Id {
+        #[diesel(sql_type = BigInt)]
+        id: i64,
+    }
+
+    let row: Id = diesel::sql_query(
+        "insert into jobs (kind, payload, run_at) values ($1, $2, $3) \
+         returning id",
+    )
+    .bind::<Text, _>(kind)
+    .bind::<Jsonb, _>(payload)
+    .bind::<Timestamptz, _>(run_at)
+    .get_result(conn)
+    .await?;
+
+    Ok(row.id)
+}
+
+/// Claim up to `batch_size` pending, due jobs, marking them `running` under
+/// `worker_id` and incrementing `attempts` in the same statement they were
+/// selected in, so two workers can never claim the same row and a job's
+/// `attempts` always reflects the count of the run in progress (what
+/// [`fail`] compares against `max_attempts`).
+pub(crate) async fn claim(
+    conn: &mut impl WriteableConnection,
+    worker_id: &str,
+    batch_size: i64,
+) -> Result<Vec<ClaimedJob>, JobError> {
+    let claimed: Vec<ClaimedJob> = diesel::sql_query(
+        "update jobs set state = 'running', locked_by = $2, locked_at = now(), \
+             attempts = attempts + 1 \
+         where id in ( \
+             select id from jobs \
+             where state = 'pending' and run_at <= now() \
+             order by run_at \
+             limit $1 \
+             for update skip locked \
+         ) \
+         returning id, kind, payload, attempts, max_attempts",
+    )
+    .bind::<BigInt, _>(batch_size)
+    .bind::<Text, _>(worker_id)
+    .load(conn)
+    .await?;
+
+    Ok(claimed)
+}
+
+/// Mark a claimed job `completed`.
+pub(crate) async fn complete(
+    conn: &mut impl WriteableConnection,
+    id: i64,
+) -> Result<(), JobError> {
+    diesel::sql_query("update jobs set state = 'completed' where id = $1")
+        .bind::<BigInt, _>(id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Record a failed attempt at job `id`. If `attempts` has reached
+/// `max_attempts`, the job moves to the `dead` state; otherwise it's put
+/// back to `pending` with `run_at` pushed out by an exponential backoff.
+pub(crate) async fn fail(
+    conn: &mut impl WriteableConnection,
+    id: i64,
+    attempts: i32,
+    max_attempts: i32,
+    error: &str,
+) -> Result<(), JobError> {
+    if attempts >= max_attempts {
+        diesel::sql_query(
+            "update jobs set state = 'dead', last_error = $2 where id = $1",
+        )
+        .bind::<BigInt, _>(id)
+        .bind::<Text, _>(error)
+        .execute(conn)
+        .await?;
+    } else {
+        // Diesel's `chrono` feature has no `ToSql<Interval, Pg>` for
+        // `chrono::Duration` (Postgres intervals are backed by
+        // `PgInterval`, not a chrono type), so the offset is computed in
+        // SQL from a plain seconds count instead.
+        diesel::sql_query(
+            "update jobs set state = 'pending', \
+             run_at = now() + make_interval(secs => $2), \
+             last_error = $3 where id = $1",
+        )
+        .bind::<BigInt, _>(id)
+        .bind::<Double, _>(backoff(attempts))
+        .bind::<Text, _>(error)
+        .execute(conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Exponential backoff between retries of a failed job: `2^attempts`
+/// seconds, capped at 15 minutes.
+fn backoff(attempts: i32) -> f64 {
+    2f64.powi(attempts.max(0)).min(15.0 * 60.0)
+}
+
+/// Run [`claim`] inside its own transaction and return the claimed rows,
+/// used by [`Worker`](crate::jobs::Worker) to claim a batch.
+pub(crate) async fn claim_batch(
+    conn: &mut PgWriteConn,
+    worker_id: &str,
+    batch_size: i64,
+) -> Result<Vec<ClaimedJob>, JobError> {
+    conn.transaction(|conn| claim(conn, worker_id, batch_size).scope_boxed())
+        .await
+}
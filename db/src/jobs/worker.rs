@@ -0,0 +1,259 @@
+//! Polling the `jobs` table and running handlers for claimed rows.
+
+use std::time::Duration;
+
+use buildstructor::buildstructor;
+use diesel::sql_types::Double;
+use diesel_async::RunQueryDsl;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+use super::{
+    handler::HandlerRegistry,
+    queue::{self, JobError},
+};
+use crate::{PgPool, ReadWrite};
+
+/// Polls a [`PgPool<ReadWrite>`] for due jobs and runs them through a
+/// [`HandlerRegistry`], rescheduling failures with backoff and reclaiming
+/// jobs left behind by crashed workers.
+#[derive(Clone)]
+pub struct Worker {
+    pool: PgPool<ReadWrite>,
+    handlers: HandlerRegistry,
+    worker_id: String,
+    poll_interval: Duration,
+    batch_size: i64,
+    lease_timeout: Duration,
+    cancel: CancellationToken,
+}
+
+#[buildstructor]
+impl Worker {
+    /// Build a new worker. `worker_id` defaults to a value unique to this
+    /// process; set it explicitly if you run more than one worker per
+    /// process.
+    #[builder]
+    pub fn new(
+        pool: PgPool<ReadWrite>,
+        handlers: HandlerRegistry,
+        worker_id: Option<String>,
+        poll_interval: Option<Duration>,
+        batch_size: Option<i64>,
+        lease_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            pool,
+            handlers,
+            worker_id: worker_id
+                .unwrap_or_else(|| format!("worker-{}", std::process::id())),
+            poll_interval: poll_interval.unwrap_or(Duration::from_secs(1)),
+            batch_size: batch_size.unwrap_or(10),
+            lease_timeout: lease_timeout.unwrap_or(Duration::from_secs(5 * 60)),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// A token that can be used to cancel [`Worker::run`] from elsewhere,
+    /// e.g. on graceful shutdown.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Run the poll loop until cancelled via [`Worker::cancellation_token`].
+    pub async fn run(&self) -> Result<(), JobError> {
+        loop {
+            tokio::select! {
+                () = self.cancel.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(self.poll_interval) => {}
+            }
+
+            if let Err(err) = self.reclaim_stale().await {
+                error!(%err, "failed to reclaim stale jobs");
+            }
+
+            if let Err(err) = self.run_once().await {
+                error!(%err, "failed to claim jobs");
+            }
+        }
+    }
+
+    /// Claim one batch of due jobs and run their handlers to completion.
+    /// Exposed separately from [`Worker::run`] for tests and one-shot
+    /// draining.
+    pub async fn run_once(&self) -> Result<usize, JobError> {
+        let mut conn = self.pool.get().await?;
+        let claimed =
+            queue::claim_batch(&mut conn, &self.worker_id, self.batch_size)
+                .await?;
+        let claimed_count = claimed.len();
+
+        // Release this connection back to the pool before running handlers:
+        // each job below checks out its own, and holding this one idle for
+        // the rest of the loop would needlessly tie up a second connection
+        // (or, against a pool sized for exactly one, deadlock on it).
+        drop(conn);
+
+        for job in claimed {
+            let handler = self.handlers.get(&job.kind).cloned();
+            let result = match handler {
+                Some(handler) => handler(job.payload).await,
+                None => Err(format!("no handler registered for kind {:?}", job.kind)
+                    .into()),
+            };
+
+            let mut conn = self.pool.get().await?;
+            match result {
+                Ok(()) => {
+                    if let Err(err) = queue::complete(&mut conn, job.id).await {
+                        error!(%err, job_id = job.id, "failed to mark job completed");
+                    }
+                }
+                Err(err) => {
+                    warn!(job_id = job.id, kind = %job.kind, %err, "job handler failed");
+                    if let Err(err) = queue::fail(
+                        &mut conn,
+                        job.id,
+                        job.attempts,
+                        job.max_attempts,
+                        &err.to_string(),
+                    )
+                    .await
+                    {
+                        error!(%err, job_id = job.id, "failed to reschedule job");
+                    }
+                }
+            }
+        }
+
+        Ok(claimed_count)
+    }
+
+    /// Return jobs stuck `running` past `lease_timeout` (their worker likely
+    /// crashed mid-job) to `pending` so another worker can pick them up.
+    async fn reclaim_stale(&self) -> Result<u64, JobError> {
+        let mut conn = self.pool.get().await?;
+
+        // See the comment in `queue::fail` on why this binds seconds and
+        // builds the interval in SQL rather than binding a `chrono::Duration`
+        // directly.
+        diesel::sql_query(
+            "update jobs set state = 'pending', locked_by = null, locked_at = null \
+             where state = 'running' \
+             and locked_at < now() - make_interval(secs => $1)",
+        )
+        .bind::<Double, _>(self.lease_timeout.as_secs_f64())
+        .execute(&mut conn)
+        .await
+        .map(|rows| rows as u64)
+        .map_err(JobError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use diesel::{
+        QueryableByName,
+        sql_types::{BigInt, Integer, Text},
+    };
+
+    use super::*;
+    use crate::Pool;
+
+    #[derive(QueryableByName)]
+    struct JobState {
+        #[diesel(sql_type = Text)]
+        state: String,
+
+        #[diesel(sql_type = Integer)]
+        attempts: i32,
+    }
+
+    async fn test_pool() -> PgPool<ReadWrite> {
+        let pool = Pool::rw_builder()
+            .database_url(env::var("DATABASE_URL").unwrap())
+            .test_mode(true)
+            .max_size(1)
+            .build()
+            .unwrap();
+
+        let mut conn = pool.get().await.unwrap();
+        diesel::sql_query(
+            "create table if not exists jobs ( \
+                 id bigserial primary key, \
+                 kind text not null, \
+                 payload jsonb not null, \
+                 run_at timestamptz not null default now(), \
+                 attempts integer not null default 0, \
+                 max_attempts integer not null default 5, \
+                 state text not null default 'pending', \
+                 locked_by text, \
+                 locked_at timestamptz, \
+                 last_error text \
+             )",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+        pool
+    }
+
+    async fn job_state(pool: &PgPool<ReadWrite>, id: i64) -> JobState {
+        let mut conn = pool.get().await.unwrap();
+        diesel::sql_query("select state, attempts from jobs where id = $1")
+            .bind::<BigInt, _>(id)
+            .get_result(&mut conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_job_dies_after_max_attempts() {
+        let pool = test_pool().await;
+
+        let id = {
+            let mut conn = pool.get().await.unwrap();
+            let id = queue::enqueue(&mut conn, "always_fails", serde_json::json!({}))
+                .await
+                .unwrap();
+            diesel::sql_query("update jobs set max_attempts = 2 where id = $1")
+                .bind::<BigInt, _>(id)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            id
+        };
+
+        let worker = Worker::builder()
+            .pool(pool.clone())
+            .handlers(
+                HandlerRegistry::new()
+                    .register("always_fails", |_payload| async { Err("boom".into()) }),
+            )
+            .build();
+
+        worker.run_once().await.unwrap();
+        let state = job_state(&pool, id).await;
+        assert_eq!(state.state, "pending");
+        assert_eq!(state.attempts, 1);
+
+        // The failure pushed `run_at` out by the backoff; make the job due
+        // again so the next `run_once` claims it.
+        {
+            let mut conn = pool.get().await.unwrap();
+            diesel::sql_query("update jobs set run_at = now() where id = $1")
+                .bind::<BigInt, _>(id)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        }
+
+        worker.run_once().await.unwrap();
+        let state = job_state(&pool, id).await;
+        assert_eq!(state.state, "dead");
+        assert_eq!(state.attempts, 2);
+    }
+}
@@ -1,31 +1,54 @@
-//! Thin wrapper around diesel-async/deadpool_postgres that provides 2 primary
+//! Thin wrapper around diesel-async/deadpool_postgres that provides 4 primary
 //! features:
 //!
 //! 1. Read/Write differentiation, captured in the pool type;
 //! 2. Ability to switch to "test mode" if constructing a pool for testing that
-//!    runs everything in a transaction that gets rolled back by default.
+//!    runs everything in a transaction that gets rolled back by default;
+//! 3. Generic support for any diesel-async backend, rather than being
+//!    hardcoded to Postgres (see [`Backend`] and the [`PgPool`]/[`MysqlPool`]
+//!    aliases);
+//! 4. A durable, Postgres-backed background job queue (see the [`jobs`]
+//!    module).
 
 use buildstructor::buildstructor;
 use diesel_async::{
     AsyncConnection, AsyncPgConnection,
-    pooled_connection::{
-        AsyncDieselConnectionManager,
-        deadpool::{self, BuildError, Hook, Object, PoolError},
-    },
+    pooled_connection::deadpool::{self, BuildError, Hook, Object, PoolError},
     scoped_futures::{ScopedBoxFuture, ScopedFutureExt},
 };
 use std::{
     marker::PhantomData,
     ops::{Deref, DerefMut},
     thread,
+    time::Duration,
 };
 
+mod backend;
+pub mod jobs;
+mod migrations;
+#[cfg(feature = "mysql")]
+mod mysql;
+mod retry;
+mod tls;
+
 /// Re-export diesel for use by consumers.
 pub use diesel;
 
 /// Re-export diesel_async for use by consumers.
 pub use diesel_async;
 
+pub use backend::Backend;
+pub use migrations::MigrationError;
+pub use retry::RetryConfig;
+pub use tls::TlsConfig;
+
+/// A [`Pool`] connecting over Postgres.
+pub type PgPool<T> = Pool<T, AsyncPgConnection>;
+
+/// A [`Pool`] connecting over MySQL. Requires the `mysql` cargo feature.
+#[cfg(feature = "mysql")]
+pub type MysqlPool<T> = Pool<T, diesel_async::AsyncMysqlConnection>;
+
 /// Unit-struct to mark pools and connections as read-only.
 #[derive(Clone, Copy, Debug)]
 pub struct ReadOnly;
@@ -35,31 +58,47 @@ pub struct ReadOnly;
 pub struct ReadWrite;
 
 /// Our own pool type that "taints" a pool with whether it's read/write or
-/// read-only.
+/// read-only, generic over the diesel-async backend it connects with
+/// (defaulting to Postgres).
 #[derive(Clone)]
-pub struct Pool<T> {
+pub struct Pool<T, Conn: Backend = AsyncPgConnection> {
     /// Underlying deadpool pool that actually manages the connections.
-    pool: deadpool::Pool<AsyncPgConnection>,
+    pool: deadpool::Pool<Conn>,
+
+    /// Default retry behavior handed to connections checked out of this
+    /// pool, used by [`PooledConnection::transaction_with_retry`].
+    retry: RetryConfig,
 
     /// Marker to keep track of whether this is read/write or read-only.
     rw: PhantomData<T>,
 }
 
 #[buildstructor]
-impl<T: Send> Pool<T> {
+impl<T: Send, Conn: Backend> Pool<T, Conn> {
     /// Build a new pool, optionally enabling "test mode", which will run all
     /// queries in transactions to ensure a clean database, max pool size, etc.
+    ///
+    /// `options` is backend-specific configuration, e.g. a [`TlsConfig`] for
+    /// `Pool<T, AsyncPgConnection>`.
     #[builder]
     pub fn new(
         database_url: String,
         test_mode: Option<bool>,
         max_size: Option<usize>,
+        options: Option<Conn::Options>,
+        max_retries: Option<u32>,
+        retry_base_delay: Option<Duration>,
     ) -> Result<Self, BuildError> {
-        // The manager is responsible for knowing how to get a "thing" from the
-        // pool. In this case, postgres connections.
-        let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(
-            database_url,
-        );
+        let default_retry = RetryConfig::default();
+        let retry = RetryConfig {
+            max_attempts: max_retries.unwrap_or(default_retry.max_attempts),
+            base_delay: retry_base_delay.unwrap_or(default_retry.base_delay),
+        };
+
+        // The manager is responsible for knowing how to get a "thing" from
+        // the pool. Each backend builds its own, since e.g. only Postgres
+        // currently knows how to apply `options` (TLS settings).
+        let manager = Conn::manager(database_url, options);
 
         let pool = deadpool::Pool::builder(manager)
             .max_size(max_size.unwrap_or(
@@ -71,7 +110,7 @@ impl<T: Send> Pool<T> {
                 // This post-create hook will enable the test
                 // transaction mode when running tests. This keeps the
                 // DB clean during multiple or parallel test runs.
-                move |conn: &mut AsyncPgConnection, _metrics| {
+                move |conn: &mut Conn, _metrics| {
                     Box::pin(async move {
                         if test_mode.unwrap_or(false) {
                             conn.begin_test_transaction().await.unwrap();
@@ -85,6 +124,7 @@ impl<T: Send> Pool<T> {
 
         Ok(Self {
             pool,
+            retry,
             rw: PhantomData,
         })
     }
@@ -93,44 +133,57 @@ impl<T: Send> Pool<T> {
     /// connection into a type that tracks whether it's read-only or read/write.
     pub async fn get(
         &self,
-    ) -> Result<PooledConnection<T, Object<AsyncPgConnection>>, PoolError> {
+    ) -> Result<PooledConnection<T, Object<Conn>>, PoolError> {
         Ok(PooledConnection {
             conn: self.pool.get().await?,
+            retry: self.retry,
             rw: PhantomData,
         })
     }
 }
 
 #[buildstructor]
-impl Pool<ReadWrite> {
+impl<Conn: Backend> Pool<ReadWrite, Conn> {
     /// Convenience constructor to allow building a read/write pool.
     #[builder(entry = "rw_builder")]
     pub fn new_rw(
         database_url: String,
         test_mode: Option<bool>,
         max_size: Option<usize>,
+        options: Option<Conn::Options>,
+        max_retries: Option<u32>,
+        retry_base_delay: Option<Duration>,
     ) -> Result<Self, BuildError> {
         Self::builder()
             .database_url(database_url)
             .and_test_mode(test_mode)
             .and_max_size(max_size)
+            .and_options(options)
+            .and_max_retries(max_retries)
+            .and_retry_base_delay(retry_base_delay)
             .build()
     }
 }
 
 #[buildstructor]
-impl Pool<ReadOnly> {
+impl<Conn: Backend> Pool<ReadOnly, Conn> {
     /// Convenience constructor to allow building a read-only pool.
     #[builder(entry = "ro_builder")]
     pub fn new_ro(
         database_url: String,
         test_mode: Option<bool>,
         max_size: Option<usize>,
+        options: Option<Conn::Options>,
+        max_retries: Option<u32>,
+        retry_base_delay: Option<Duration>,
     ) -> Result<Self, BuildError> {
         Self::builder()
             .database_url(database_url)
             .and_test_mode(test_mode)
             .and_max_size(max_size)
+            .and_options(options)
+            .and_max_retries(max_retries)
+            .and_retry_base_delay(retry_base_delay)
             .build()
     }
 }
@@ -143,10 +196,15 @@ where
     C::Target: AsyncConnection,
 {
     conn: C,
+
+    /// Retry behavior inherited from the pool this connection was checked
+    /// out of, used by [`PooledConnection::transaction_with_retry`].
+    retry: RetryConfig,
+
     rw: PhantomData<T>,
 }
 
-impl<T: Send> PooledConnection<T, Object<AsyncPgConnection>> {
+impl<T: Send, Conn: Backend> PooledConnection<T, Object<Conn>> {
     /// This is probably the gnarliest bit of this library. This allows passing
     /// a closure/function that uses a PooledConnection into one of our
     /// type-tainted connections. This ensures that even when using
@@ -160,7 +218,7 @@ impl<T: Send> PooledConnection<T, Object<AsyncPgConnection>> {
         // This is the callback function you provide, that basically takes a
         // connection, and returns a result.
         F: for<'r> FnOnce(
-                &'r mut PooledConnection<T, &mut AsyncPgConnection>,
+                &'r mut PooledConnection<T, &mut Conn>,
             ) -> ScopedBoxFuture<'a, 'r, Result<R, E>>
             + Send
             + 'a,
@@ -170,14 +228,17 @@ impl<T: Send> PooledConnection<T, Object<AsyncPgConnection>> {
         R: Send + 'a,
         'a: 'conn,
     {
-        // Start a transaction via the AsyncPgConnection...
+        let retry = self.retry;
+
+        // Start a transaction via the underlying connection...
         self.conn
             .transaction(|conn| {
-                // ... wrap the new &mut AsyncPgConnection that's in a
-                // transaction into our PooledConnection to preserve read/write
-                // vs. read-only context.
-                let mut conn = PooledConnection::<T, &mut AsyncPgConnection> {
+                // ... wrap the new &mut Conn that's in a transaction into our
+                // PooledConnection to preserve read/write vs. read-only
+                // context.
+                let mut conn = PooledConnection::<T, &mut Conn> {
                     conn,
+                    retry,
                     rw: PhantomData,
                 };
 
@@ -186,12 +247,59 @@ impl<T: Send> PooledConnection<T, Object<AsyncPgConnection>> {
             })
             .await
     }
+
+    /// Like [`PooledConnection::transaction`], but retries the callback if it
+    /// aborts with a transient SQLSTATE `40001` (serialization_failure) or
+    /// `40P01` (deadlock_detected) error, rolling back and re-invoking it
+    /// with exponential backoff + jitter between attempts. Surfaces the last
+    /// error once the pool's configured `max_attempts` is exhausted.
+    ///
+    /// Because the callback may run more than once, it must be `FnMut`
+    /// rather than `FnOnce`.
+    pub async fn transaction_with_retry<'a, 'conn, R, F>(
+        &'conn mut self,
+        mut callback: F,
+    ) -> Result<R, diesel::result::Error>
+    where
+        F: for<'r> FnMut(
+                &'r mut PooledConnection<T, &mut Conn>,
+            )
+                -> ScopedBoxFuture<'a, 'r, Result<R, diesel::result::Error>>
+            + Send
+            + 'a,
+        R: Send + 'a,
+        'a: 'conn,
+    {
+        let retry = self.retry;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            match self.transaction(&mut callback).await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt < retry.max_attempts && retry::is_retryable(&err) => {
+                    tokio::time::sleep(retry.backoff(attempt)).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl<T: Send, Conn: Backend> PooledConnection<T, Object<Conn>> {
+    /// Consume this connection, handing back the underlying pooled
+    /// connection object. Used internally by APIs that need to take
+    /// ownership of the connection itself, e.g. the migration runner.
+    pub(crate) fn into_inner(self) -> Object<Conn> {
+        self.conn
+    }
 }
 
 /// Implement Deref for our connections we get from the pool. This allows using
 /// these r/w-tainted connections natively with diesel functions.
-impl<T: Send> Deref for PooledConnection<T, Object<AsyncPgConnection>> {
-    type Target = AsyncPgConnection;
+impl<T: Send, Conn: Backend> Deref for PooledConnection<T, Object<Conn>> {
+    type Target = Conn;
 
     fn deref(&self) -> &Self::Target {
         &*self.conn
@@ -200,17 +308,17 @@ impl<T: Send> Deref for PooledConnection<T, Object<AsyncPgConnection>> {
 
 /// Implement DerefMut for our connections we get from the pool. This allows
 /// using these r/w-tainted connections natively with diesel functions.
-impl<T: Send> DerefMut for PooledConnection<T, Object<AsyncPgConnection>> {
-    fn deref_mut(&mut self) -> &mut AsyncPgConnection {
+impl<T: Send, Conn: Backend> DerefMut for PooledConnection<T, Object<Conn>> {
+    fn deref_mut(&mut self) -> &mut Conn {
         self.conn.deref_mut()
     }
 }
 
 /// Implement AsMut, which allows "cheap mutable-to-mutable reference
 /// conversion". It's suggested if you implement DerefMut.
-impl<T: Send, U> AsMut<U> for PooledConnection<T, Object<AsyncPgConnection>>
+impl<T: Send, Conn: Backend, U> AsMut<U> for PooledConnection<T, Object<Conn>>
 where
-    <PooledConnection<T, Object<AsyncPgConnection>> as Deref>::Target: AsMut<U>,
+    <PooledConnection<T, Object<Conn>> as Deref>::Target: AsMut<U>,
 {
     fn as_mut(&mut self) -> &mut U {
         self.deref_mut().as_mut()
@@ -221,8 +329,8 @@ where
 /// part of the glue that allows us to use our ReadableConnection and
 /// WriteableConnection traits across both pool connections and raw connections
 /// (which we have to work with in transactions).
-impl<T: Send> Deref for PooledConnection<T, &mut AsyncPgConnection> {
-    type Target = AsyncPgConnection;
+impl<T: Send, Conn: Backend> Deref for PooledConnection<T, &mut Conn> {
+    type Target = Conn;
 
     fn deref(&self) -> &Self::Target {
         self.conn
@@ -233,60 +341,191 @@ impl<T: Send> Deref for PooledConnection<T, &mut AsyncPgConnection> {
 /// is part of the glue that allows us to use our ReadableConnection and
 /// WriteableConnection traits across both pool connections and raw connections
 /// (which we have to work with in transactions).
-impl<T: Send> DerefMut for PooledConnection<T, &mut AsyncPgConnection> {
-    fn deref_mut(&mut self) -> &mut AsyncPgConnection {
+impl<T: Send, Conn: Backend> DerefMut for PooledConnection<T, &mut Conn> {
+    fn deref_mut(&mut self) -> &mut Conn {
         self.conn
     }
 }
 
+impl<'c, T: Send, Conn: Backend> PooledConnection<T, &'c mut Conn> {
+    /// Reborrow this connection with a shorter lifetime. This lets us hand
+    /// the same in-transaction connection to a nested call without moving it
+    /// out of the caller's `&mut`.
+    pub fn reborrow(&mut self) -> PooledConnection<T, &mut Conn> {
+        PooledConnection {
+            conn: &mut *self.conn,
+            retry: self.retry,
+            rw: PhantomData,
+        }
+    }
+}
+
 /// Implement AsMut for mutable borrows of a connection. This is a no-op, but
 /// is part of the glue that allows us to use our ReadableConnection and
 /// WriteableConnection traits across both pool connections and raw connections
 /// (which we have to work with in transactions).
-impl<T: Send> AsMut<AsyncPgConnection>
-    for PooledConnection<T, &mut AsyncPgConnection>
-{
-    fn as_mut(&mut self) -> &mut AsyncPgConnection {
+impl<T: Send, Conn: Backend> AsMut<Conn> for PooledConnection<T, &mut Conn> {
+    fn as_mut(&mut self) -> &mut Conn {
         self.conn
     }
 }
 
 /// Mark a connection as usable for reads. It says nothing about whether you can
 /// write to it.
-pub trait ReadableConnection:
-    Deref<Target = AsyncPgConnection> + DerefMut + Send
+pub trait ReadableConnection<Conn: Backend = AsyncPgConnection>:
+    Deref<Target = Conn> + DerefMut + Send
 {
 }
 
 /// Blanket implementation of ReadableConnection for any connection that's
 /// writeable.
-impl<T> ReadableConnection for T where T: WriteableConnection {}
+impl<T, Conn: Backend> ReadableConnection<Conn> for T where
+    T: WriteableConnection<Conn>
+{
+}
 
 /// Connections straight from the read-only pool are readable.
-impl ReadableConnection
-    for PooledConnection<ReadOnly, Object<AsyncPgConnection>>
+impl<Conn: Backend> ReadableConnection<Conn>
+    for PooledConnection<ReadOnly, Object<Conn>>
 {
 }
 
 /// Borrowed connections from read-only pools that we deal with in transactions
 /// are readable.
-impl ReadableConnection for PooledConnection<ReadOnly, &mut AsyncPgConnection> {}
+impl<Conn: Backend> ReadableConnection<Conn>
+    for PooledConnection<ReadOnly, &mut Conn>
+{
+}
 
 /// Mark a connection as usable for writes. Write connections can also be used
 /// for reads.
-pub trait WriteableConnection:
-    Deref<Target = AsyncPgConnection> + DerefMut + Send
+pub trait WriteableConnection<Conn: Backend = AsyncPgConnection>:
+    Deref<Target = Conn> + DerefMut + Send
 {
 }
 
 /// Connections from write pools are usable as WriteableConnections.
-impl WriteableConnection
-    for PooledConnection<ReadWrite, Object<AsyncPgConnection>>
+impl<Conn: Backend> WriteableConnection<Conn>
+    for PooledConnection<ReadWrite, Object<Conn>>
 {
 }
 
 /// Borrowed connections from write pools are usable as WriteableConnections.
-impl WriteableConnection
-    for PooledConnection<ReadWrite, &mut AsyncPgConnection>
+impl<Conn: Backend> WriteableConnection<Conn>
+    for PooledConnection<ReadWrite, &mut Conn>
 {
 }
+
+/// Lets a query function take either a pool or an already-open connection,
+/// so the same function can run standalone (checking out its own connection)
+/// or nested inside a `transaction(|conn| ...)` closure (reusing the
+/// in-flight one). Functions take this as `&mut DbHandle<'_, '_, T>` and
+/// resolve it with [`DbHandle::get_conn`].
+pub enum DbHandle<'a, 'b, T: Send, Conn: Backend = AsyncPgConnection> {
+    /// Not yet connected to anything; checks out a fresh connection from the
+    /// pool each time it's resolved.
+    Pool(&'a Pool<T, Conn>),
+
+    /// Already inside a transaction; resolving this reborrows the existing
+    /// connection rather than checking out a new one. `'b` is independent of
+    /// `'a` so the outer reference doesn't have to borrow the inner
+    /// connection for its entire lifetime (an `&'a mut T<'a>` would be
+    /// effectively unconstructable for nested transactions).
+    Conn(&'a mut PooledConnection<T, &'b mut Conn>),
+}
+
+impl<'a, 'b, T: Send, Conn: Backend> DbHandle<'a, 'b, T, Conn> {
+    /// Resolve the handle into something that derefs to the underlying
+    /// connection type and preserves the `ReadOnly`/`ReadWrite` tainting: a
+    /// fresh pooled connection for the `Pool` variant, or a reborrow of the
+    /// existing connection for the `Conn` variant.
+    pub async fn get_conn(
+        &mut self,
+    ) -> Result<DbHandleConn<'_, T, Conn>, PoolError> {
+        Ok(match self {
+            DbHandle::Pool(pool) => DbHandleConn::Pool(pool.get().await?),
+            DbHandle::Conn(conn) => DbHandleConn::Conn(conn.reborrow()),
+        })
+    }
+}
+
+/// The connection returned by [`DbHandle::get_conn`]. Derefs to the
+/// underlying connection either way, so it can be used anywhere a
+/// `ReadableConnection`/`WriteableConnection` is expected.
+pub enum DbHandleConn<'a, T: Send, Conn: Backend = AsyncPgConnection> {
+    /// A connection freshly checked out of the pool.
+    Pool(PooledConnection<T, Object<Conn>>),
+
+    /// A reborrow of an already-open, in-transaction connection.
+    Conn(PooledConnection<T, &'a mut Conn>),
+}
+
+impl<T: Send, Conn: Backend> Deref for DbHandleConn<'_, T, Conn> {
+    type Target = Conn;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DbHandleConn::Pool(conn) => conn,
+            DbHandleConn::Conn(conn) => conn,
+        }
+    }
+}
+
+impl<T: Send, Conn: Backend> DerefMut for DbHandleConn<'_, T, Conn> {
+    fn deref_mut(&mut self) -> &mut Conn {
+        match self {
+            DbHandleConn::Pool(conn) => conn,
+            DbHandleConn::Conn(conn) => conn,
+        }
+    }
+}
+
+/// A resolved `DbHandle` to a read-only pool is readable, same as any other
+/// read-only connection.
+impl<Conn: Backend> ReadableConnection<Conn> for DbHandleConn<'_, ReadOnly, Conn> {}
+
+/// A resolved `DbHandle` to a read/write pool is writeable (and, via the
+/// blanket impl, readable too).
+impl<Conn: Backend> WriteableConnection<Conn>
+    for DbHandleConn<'_, ReadWrite, Conn>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// A function written against `DbHandle` should run standalone (checking
+    /// out its own connection) and also thread through an existing
+    /// transaction, reusing that connection instead of deadlocking on a
+    /// single-connection pool.
+    async fn touch(handle: &mut DbHandle<'_, '_, ReadWrite>) -> Result<(), PoolError> {
+        handle.get_conn().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_db_handle_through_transaction() {
+        let pool = Pool::rw_builder()
+            .database_url(env::var("DATABASE_URL").unwrap())
+            .test_mode(true)
+            .max_size(1)
+            .build()
+            .unwrap();
+
+        touch(&mut DbHandle::Pool(&pool)).await.unwrap();
+
+        let mut conn = pool.get().await.unwrap();
+        conn.transaction(|conn| {
+            async move {
+                touch(&mut DbHandle::Conn(conn)).await.unwrap();
+                Ok::<_, diesel::result::Error>(())
+            }
+            .scope_boxed()
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+//! Running embedded Diesel migrations without linking a synchronous,
+//! libpq-backed `diesel` connection.
+
+use diesel_async::{
+    async_connection_wrapper::AsyncConnectionWrapper,
+    pooled_connection::deadpool::{Object, PoolError},
+};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
+
+use crate::{Backend, Pool, ReadWrite};
+
+/// Errors from [`Pool::run_pending_migrations`].
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    /// Failed to check out a connection from the pool.
+    #[error("failed to check out a connection: {0}")]
+    Pool(#[from] PoolError),
+
+    /// The migration harness itself returned an error.
+    #[error("failed to run pending migrations: {0}")]
+    Migration(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The blocking task running the harness panicked.
+    #[error("migration task panicked: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+impl<Conn: Backend> Pool<ReadWrite, Conn> {
+    /// Apply any pending `migrations`, returning the versions that were
+    /// applied.
+    ///
+    /// `diesel_migrations::MigrationHarness` is synchronous, so it can't run
+    /// directly against an async connection. Instead we check out one
+    /// pooled connection and wrap it in diesel-async's
+    /// `AsyncConnectionWrapper`, a shim that implements the blocking
+    /// `diesel::Connection` trait on top of an async connection by driving
+    /// its futures to completion, then run the harness on a `spawn_blocking`
+    /// task. This gives callers a one-call way to apply
+    /// `diesel_migrations::embed_migrations!()` output on boot without
+    /// pulling in a second, synchronously-linked dependency tree.
+    pub async fn run_pending_migrations(
+        &self,
+        migrations: EmbeddedMigrations,
+    ) -> Result<Vec<diesel::migration::MigrationVersion<'static>>, MigrationError>
+    {
+        let conn = self.get().await?.into_inner();
+        let mut wrapper = AsyncConnectionWrapper::<Object<Conn>>::from(conn);
+
+        tokio::task::spawn_blocking(move || {
+            let versions = wrapper
+                .run_pending_migrations(migrations)
+                .map_err(MigrationError::Migration)?;
+
+            Ok(versions.into_iter().map(|v| v.as_owned()).collect())
+        })
+        .await?
+    }
+}
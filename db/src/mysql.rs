@@ -0,0 +1,21 @@
+//! MySQL backend support, enabled via the `mysql` cargo feature.
+
+use std::convert::Infallible;
+
+use diesel_async::{
+    AsyncMysqlConnection, pooled_connection::AsyncDieselConnectionManager,
+};
+
+use crate::Backend;
+
+impl Backend for AsyncMysqlConnection {
+    /// MySQL connections don't support any backend-specific options yet.
+    type Options = Infallible;
+
+    fn manager(
+        database_url: String,
+        _options: Option<Infallible>,
+    ) -> AsyncDieselConnectionManager<Self> {
+        AsyncDieselConnectionManager::new(database_url)
+    }
+}
@@ -0,0 +1,75 @@
+//! Automatic retry of transactions that fail with a retryable Postgres
+//! error, e.g. `40001` (serialization_failure) or `40P01`
+//! (deadlock_detected) under `SERIALIZABLE`/`REPEATABLE READ` isolation.
+
+use std::time::Duration;
+
+use diesel::result::Error as DieselError;
+
+/// SQLSTATE for `serialization_failure`.
+const SERIALIZATION_FAILURE: &str = "40001";
+
+/// SQLSTATE for `deadlock_detected`.
+const DEADLOCK_DETECTED: &str = "40P01";
+
+/// Configures how many times, and how long to wait between, retries of a
+/// transaction that aborts with a retryable error.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    /// Maximum number of attempts to make before giving up and surfacing the
+    /// last error. `1` means "no retries".
+    pub max_attempts: u32,
+
+    /// Base delay used for the exponential backoff between attempts. The
+    /// actual delay is `base_delay * 2^(attempt - 1)`, plus jitter.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(20),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The delay to sleep before the given 1-indexed `attempt` is retried:
+    /// exponential backoff from `base_delay`, plus up to 100% jitter so that
+    /// callers contending on the same rows don't retry in lockstep.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << attempt.saturating_sub(1).min(16));
+
+        let jitter = Duration::from_nanos(
+            (std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .subsec_nanos() as u64)
+                % (exp.as_nanos() as u64 + 1),
+        );
+
+        exp + jitter
+    }
+}
+
+/// Whether `err` is a transient error under `SERIALIZABLE`/`REPEATABLE READ`
+/// isolation that's expected to succeed on retry.
+pub(crate) fn is_retryable(err: &DieselError) -> bool {
+    match err {
+        // Match on the SQLSTATE regardless of `DatabaseErrorKind`: diesel
+        // maps `40001` (serialization_failure) to
+        // `DatabaseErrorKind::SerializationFailure`, not `Unknown`, so
+        // matching only `Unknown` would silently never retry the primary
+        // case this module exists for.
+        DieselError::DatabaseError(_, info) => {
+            matches!(
+                info.code(),
+                Some(SERIALIZATION_FAILURE) | Some(DEADLOCK_DETECTED)
+            )
+        }
+        _ => false,
+    }
+}
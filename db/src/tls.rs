@@ -0,0 +1,160 @@
+//! Optional rustls-based TLS for connecting to Postgres, for providers that
+//! require TLS (refusing plaintext connections) and/or need custom
+//! certificate validation.
+
+use std::sync::Arc;
+
+use diesel_async::{
+    AsyncPgConnection,
+    pooled_connection::{AsyncDieselConnectionManager, ManagerConfig},
+};
+use futures_util::future::BoxFuture;
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::Backend;
+
+/// Configures a pool to connect over TLS instead of handing the raw
+/// `database_url` to diesel-async's default (libpq-style) connector. Pass
+/// this as `Pool`'s `options` (see [`Backend::Options`](crate::Backend)).
+///
+/// Building the underlying `rustls::ClientConfig` requires a process-default
+/// `CryptoProvider` to already be installed (e.g. via
+/// `rustls::crypto::ring::default_provider().install_default()`), since
+/// `rustls::ClientConfig::builder()` panics without one. The
+/// "accept any certificate" verifier below hardcodes the `ring` provider's
+/// signature schemes, so install `ring`, not
+/// `aws-lc-rs`, if you use `TlsConfig::accept_invalid_certs`.
+#[derive(Clone)]
+pub struct TlsConfig {
+    verifier: Arc<dyn ServerCertVerifier>,
+}
+
+impl TlsConfig {
+    /// Use a custom `rustls` certificate verifier, e.g. one that pins a
+    /// specific CA or leaf certificate.
+    pub fn new(verifier: Arc<dyn ServerCertVerifier>) -> Self {
+        Self { verifier }
+    }
+
+    /// Accept any certificate, including self-signed ones, without
+    /// validation.
+    ///
+    /// This disables a core security guarantee of TLS (protection against
+    /// man-in-the-middle attacks) and must only be used against local or
+    /// otherwise trusted development databases.
+    pub fn accept_invalid_certs() -> Self {
+        Self::new(Arc::new(NoCertificateVerification))
+    }
+
+    /// Build the `ManagerConfig` that installs this TLS setup on an
+    /// `AsyncDieselConnectionManager<AsyncPgConnection>`.
+    pub(crate) fn into_manager_config(self) -> ManagerConfig<AsyncPgConnection> {
+        let verifier = self.verifier;
+
+        let mut config = ManagerConfig::default();
+        config.custom_setup = Box::new(move |url| {
+            let verifier = verifier.clone();
+            Self::connect(url.to_owned(), verifier)
+        });
+        config
+    }
+
+    fn connect(
+        url: String,
+        verifier: Arc<dyn ServerCertVerifier>,
+    ) -> BoxFuture<'static, diesel::ConnectionResult<AsyncPgConnection>> {
+        Box::pin(async move {
+            let tls_config = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_no_client_auth();
+            let tls = MakeRustlsConnect::new(tls_config);
+
+            let (client, conn) = tokio_postgres::connect(&url, tls)
+                .await
+                .map_err(|e| {
+                    diesel::ConnectionError::BadConnection(e.to_string())
+                })?;
+
+            // The connection object performs the actual IO to the database;
+            // it must be polled for the client to make progress, so we hand
+            // it off to its own task for the lifetime of the connection.
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    tracing::error!("postgres TLS connection driver failed: {e}");
+                }
+            });
+
+            AsyncPgConnection::try_from(client).await
+        })
+    }
+}
+
+impl Backend for AsyncPgConnection {
+    /// Postgres connections can optionally be configured with TLS.
+    type Options = TlsConfig;
+
+    fn manager(
+        database_url: String,
+        options: Option<TlsConfig>,
+    ) -> AsyncDieselConnectionManager<Self> {
+        match options {
+            Some(tls) => AsyncDieselConnectionManager::new_with_config(
+                database_url,
+                tls.into_manager_config(),
+            ),
+            None => AsyncDieselConnectionManager::new(database_url),
+        }
+    }
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+/// A `ServerCertVerifier` that accepts any certificate. See
+/// [`TlsConfig::accept_invalid_certs`].
+#[derive(Debug)]
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &rustls::pki_types::CertificateDer<'_>,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &rustls::pki_types::CertificateDer<'_>,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}